@@ -20,10 +20,58 @@
 use crate::AconfigStorageError::{self, BytesParseFail};
 use crate::{get_bucket_index, read_str_from_bytes, read_u32_from_bytes};
 use anyhow::anyhow;
-use std::fmt;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// under the "no-std" feature this crate only depends on `alloc`, so `Vec`/`String` are
+// pulled from `alloc` and `fmt` from `core` instead of `std`; this keeps the struct and (de)serialization code
+// below unchanged regardless of which feature is active
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+#[cfg(feature = "std")]
+use std::{borrow::Cow, fmt};
+#[cfg(not(feature = "std"))]
+use {
+    alloc::borrow::Cow,
+    alloc::string::String,
+    alloc::vec::Vec,
+    core::fmt,
+};
+
+/// The original, uncompressed and unchecksummed package table format version
+pub const PACKAGE_TABLE_VERSION_PLAIN: u32 = 1234;
+
+/// Package table format version with a SHA-256 checksum and optional zstd compression
+pub const PACKAGE_TABLE_VERSION_CHECKSUMMED: u32 = 1235;
+
+/// Known package table format versions
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PackageTableVersion {
+    /// see [`PACKAGE_TABLE_VERSION_PLAIN`]
+    Plain,
+    /// see [`PACKAGE_TABLE_VERSION_CHECKSUMMED`]
+    Checksummed,
+}
+
+impl PackageTableVersion {
+    fn from_version_number(version: u32) -> Result<Self, AconfigStorageError> {
+        match version {
+            PACKAGE_TABLE_VERSION_PLAIN => Ok(Self::Plain),
+            // the checksummed/compressed format depends on the `sha2`/`zstd` crates,
+            // which are std-only, so it isn't recognized in `no-std` builds
+            #[cfg(feature = "std")]
+            PACKAGE_TABLE_VERSION_CHECKSUMMED => Ok(Self::Checksummed),
+            _ => Err(AconfigStorageError::UnsupportedVersion(anyhow!(
+                "unsupported package table version {}",
+                version
+            ))),
+        }
+    }
+}
 
 /// Package table header struct
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PackageTableHeader {
     pub version: u32,
     pub container: String,
@@ -31,6 +79,11 @@ pub struct PackageTableHeader {
     pub num_packages: u32,
     pub bucket_offset: u32,
     pub node_offset: u32,
+    /// SHA-256 digest of the buckets+nodes payload, present from [`PACKAGE_TABLE_VERSION_CHECKSUMMED`] on
+    pub checksum: Option<[u8; 32]>,
+    /// Whether the buckets+nodes payload is zstd-compressed; only takes effect from
+    /// [`PACKAGE_TABLE_VERSION_CHECKSUMMED`] on, and is always false when read from a plain table
+    pub compressed: bool,
 }
 
 /// Implement debug print trait for header
@@ -46,6 +99,7 @@ impl fmt::Debug for PackageTableHeader {
             "Num of Packages: {}, Bucket Offset:{}, Node Offset: {}",
             self.num_packages, self.bucket_offset, self.node_offset
         )?;
+        writeln!(f, "Compressed: {}, Checksum: {:?}", self.compressed, self.checksum)?;
         Ok(())
     }
 }
@@ -62,25 +116,117 @@ impl PackageTableHeader {
         result.extend_from_slice(&self.num_packages.to_le_bytes());
         result.extend_from_slice(&self.bucket_offset.to_le_bytes());
         result.extend_from_slice(&self.node_offset.to_le_bytes());
+        if let Ok(PackageTableVersion::Checksummed) =
+            PackageTableVersion::from_version_number(self.version)
+        {
+            result.push(self.compressed as u8);
+            result.extend_from_slice(&self.checksum.unwrap_or([0u8; 32]));
+        }
         result
     }
 
     /// Deserialize from bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, AconfigStorageError> {
+        Self::decode(bytes).map(|(header, _)| header)
+    }
+
+    /// Deserialize from bytes, also returning the number of bytes consumed
+    fn decode(bytes: &[u8]) -> Result<(Self, usize), AconfigStorageError> {
         let mut head = 0;
-        Ok(Self {
-            version: read_u32_from_bytes(bytes, &mut head)?,
-            container: read_str_from_bytes(bytes, &mut head)?,
-            file_size: read_u32_from_bytes(bytes, &mut head)?,
-            num_packages: read_u32_from_bytes(bytes, &mut head)?,
-            bucket_offset: read_u32_from_bytes(bytes, &mut head)?,
-            node_offset: read_u32_from_bytes(bytes, &mut head)?,
-        })
+        let version = read_u32_from_bytes(bytes, &mut head)?;
+        let container = read_str_from_bytes(bytes, &mut head)?;
+        let file_size = read_u32_from_bytes(bytes, &mut head)?;
+        let num_packages = read_u32_from_bytes(bytes, &mut head)?;
+        let bucket_offset = read_u32_from_bytes(bytes, &mut head)?;
+        let node_offset = read_u32_from_bytes(bytes, &mut head)?;
+        let (compressed, checksum) = match PackageTableVersion::from_version_number(version)? {
+            PackageTableVersion::Plain => (false, None),
+            PackageTableVersion::Checksummed => {
+                let compressed = *bytes
+                    .get(head)
+                    .ok_or_else(|| BytesParseFail(anyhow!("fail to parse package table header")))?
+                    != 0;
+                head += 1;
+                let mut digest = [0u8; 32];
+                digest.copy_from_slice(bytes.get(head..head + 32).ok_or_else(|| {
+                    BytesParseFail(anyhow!("fail to parse package table header"))
+                })?);
+                head += 32;
+                (compressed, Some(digest))
+            }
+        };
+        let header = Self {
+            version,
+            container,
+            file_size,
+            num_packages,
+            bucket_offset,
+            node_offset,
+            checksum,
+            compressed,
+        };
+        Ok((header, head))
+    }
+}
+
+/// Compute the SHA-256 digest of a byte slice
+#[cfg(feature = "std")]
+fn sha256_digest(bytes: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
+/// A field's little-endian binary layout, encoded/decoded once per type
+trait BinaryField: Sized {
+    /// Serialize to bytes
+    fn encode_to(&self, out: &mut Vec<u8>);
+    /// Deserialize from bytes, also returning the number of bytes consumed
+    fn decode_from(bytes: &[u8]) -> Result<(Self, usize), AconfigStorageError>;
+}
+
+impl BinaryField for u32 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, usize), AconfigStorageError> {
+        let mut head = 0;
+        let value = read_u32_from_bytes(bytes, &mut head)?;
+        Ok((value, head))
+    }
+}
+
+impl BinaryField for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        let bytes = self.as_bytes();
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, usize), AconfigStorageError> {
+        let mut head = 0;
+        let value = read_str_from_bytes(bytes, &mut head)?;
+        Ok((value, head))
+    }
+}
+
+/// The `Option<u32>` "0 means None" convention used by `next_offset` and bucket entries
+impl BinaryField for Option<u32> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.unwrap_or(0).encode_to(out);
+    }
+
+    fn decode_from(bytes: &[u8]) -> Result<(Self, usize), AconfigStorageError> {
+        let (value, len) = u32::decode_from(bytes)?;
+        Ok((if value == 0 { None } else { Some(value) }, len))
     }
 }
 
 /// Package table node struct
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PackageTableNode {
     pub package_name: String,
     pub package_id: u32,
@@ -106,28 +252,30 @@ impl PackageTableNode {
     /// Serialize to bytes
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut result = Vec::new();
-        let name_bytes = self.package_name.as_bytes();
-        result.extend_from_slice(&(name_bytes.len() as u32).to_le_bytes());
-        result.extend_from_slice(name_bytes);
-        result.extend_from_slice(&self.package_id.to_le_bytes());
-        result.extend_from_slice(&self.boolean_offset.to_le_bytes());
-        result.extend_from_slice(&self.next_offset.unwrap_or(0).to_le_bytes());
+        self.package_name.encode_to(&mut result);
+        self.package_id.encode_to(&mut result);
+        self.boolean_offset.encode_to(&mut result);
+        self.next_offset.encode_to(&mut result);
         result
     }
 
     /// Deserialize from bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, AconfigStorageError> {
-        let mut head = 0;
-        let node = Self {
-            package_name: read_str_from_bytes(bytes, &mut head)?,
-            package_id: read_u32_from_bytes(bytes, &mut head)?,
-            boolean_offset: read_u32_from_bytes(bytes, &mut head)?,
-            next_offset: match read_u32_from_bytes(bytes, &mut head)? {
-                0 => None,
-                val => Some(val),
-            },
-        };
-        Ok(node)
+        Self::decode(bytes).map(|(node, _)| node)
+    }
+
+    /// Deserialize from bytes, also returning the number of bytes consumed
+    fn decode(bytes: &[u8]) -> Result<(Self, usize), AconfigStorageError> {
+        let mut len = 0;
+        let (package_name, n) = String::decode_from(bytes)?;
+        len += n;
+        let (package_id, n) = u32::decode_from(&bytes[len..])?;
+        len += n;
+        let (boolean_offset, n) = u32::decode_from(&bytes[len..])?;
+        len += n;
+        let (next_offset, n) = <Option<u32>>::decode_from(&bytes[len..])?;
+        len += n;
+        Ok((Self { package_name, package_id, boolean_offset, next_offset }, len))
     }
 
     /// Get the bucket index for a package table node, defined it here so the
@@ -140,6 +288,7 @@ impl PackageTableNode {
 
 /// Package table struct
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PackageTable {
     pub header: PackageTableHeader,
     pub buckets: Vec<Option<u32>>,
@@ -162,32 +311,78 @@ impl fmt::Debug for PackageTable {
 }
 
 impl PackageTable {
+    /// The format version this table was parsed as (or will be serialized as)
+    pub fn format_version(&self) -> Result<PackageTableVersion, AconfigStorageError> {
+        PackageTableVersion::from_version_number(self.header.version)
+    }
+
     /// Serialize to bytes
     pub fn as_bytes(&self) -> Vec<u8> {
-        [
-            self.header.as_bytes(),
+        let payload = [
             self.buckets.iter().map(|v| v.unwrap_or(0).to_le_bytes()).collect::<Vec<_>>().concat(),
             self.nodes.iter().map(|v| v.as_bytes()).collect::<Vec<_>>().concat(),
         ]
-        .concat()
+        .concat();
+
+        #[cfg(feature = "std")]
+        if matches!(self.format_version(), Ok(PackageTableVersion::Checksummed)) {
+            let checksum = sha256_digest(&payload);
+            let payload = if self.header.compressed {
+                zstd::stream::encode_all(&payload[..], 0)
+                    .expect("failed to zstd-compress package table")
+            } else {
+                payload
+            };
+            let header = PackageTableHeader { checksum: Some(checksum), ..self.header.clone() };
+            return [header.as_bytes(), payload].concat();
+        }
+
+        [self.header.as_bytes(), payload].concat()
     }
 
     /// Deserialize from bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, AconfigStorageError> {
-        let header = PackageTableHeader::from_bytes(bytes)?;
+        let (header, header_len) = PackageTableHeader::decode(bytes)?;
+        let version = PackageTableVersion::from_version_number(header.version)?;
+        // only consulted by the checksummed/compressed path below, which is std-only
+        #[cfg(not(feature = "std"))]
+        let _ = version;
         let num_packages = header.num_packages;
         let num_buckets = crate::get_table_size(num_packages)?;
-        let mut head = header.as_bytes().len();
+        let raw_payload = &bytes[header_len..];
+
+        #[cfg(feature = "std")]
+        let payload: Cow<[u8]> = if version == PackageTableVersion::Checksummed && header.compressed
+        {
+            Cow::Owned(zstd::stream::decode_all(raw_payload).map_err(|errmsg| {
+                BytesParseFail(anyhow!("fail to decompress package table: {}", errmsg))
+            })?)
+        } else {
+            Cow::Borrowed(raw_payload)
+        };
+        #[cfg(not(feature = "std"))]
+        let payload: Cow<[u8]> = Cow::Borrowed(raw_payload);
+
+        #[cfg(feature = "std")]
+        if version == PackageTableVersion::Checksummed
+            && header.checksum != Some(sha256_digest(&payload))
+        {
+            return Err(AconfigStorageError::FileIntegrityCheckFail(anyhow!(
+                "package table checksum mismatch, file may be corrupted or truncated"
+            )));
+        }
+
+        let mut head = 0;
         let buckets = (0..num_buckets)
-            .map(|_| match read_u32_from_bytes(bytes, &mut head).unwrap() {
+            .map(|_| match read_u32_from_bytes(&payload, &mut head).unwrap() {
                 0 => None,
                 val => Some(val),
             })
             .collect();
         let nodes = (0..num_packages)
             .map(|_| {
-                let node = PackageTableNode::from_bytes(&bytes[head..])?;
-                head += node.as_bytes().len();
+                let (node, len) = PackageTableNode::decode(&payload[head..])?;
+                head += len;
                 Ok(node)
             })
             .collect::<Result<Vec<_>, AconfigStorageError>>()
@@ -198,6 +393,81 @@ impl PackageTable {
     }
 }
 
+/// Lossless JSON/CBOR export and import, for debugging, diffing and cross-tool interop
+#[cfg(feature = "serde")]
+impl PackageTable {
+    /// Serialize to pretty-printed JSON
+    pub fn to_json(&self) -> Result<String, AconfigStorageError> {
+        serde_json::to_string_pretty(self).map_err(|errmsg| {
+            BytesParseFail(anyhow!("fail to serialize package table to json: {}", errmsg))
+        })
+    }
+
+    /// Deserialize from JSON produced by [`PackageTable::to_json`]
+    pub fn from_json(json: &str) -> Result<Self, AconfigStorageError> {
+        serde_json::from_str(json).map_err(|errmsg| {
+            BytesParseFail(anyhow!("fail to parse package table from json: {}", errmsg))
+        })
+    }
+
+    /// Serialize to a compact CBOR blob
+    pub fn to_cbor(&self) -> Result<Vec<u8>, AconfigStorageError> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).map_err(|errmsg| {
+            BytesParseFail(anyhow!("fail to serialize package table to cbor: {}", errmsg))
+        })?;
+        Ok(buf)
+    }
+
+    /// Deserialize from CBOR produced by [`PackageTable::to_cbor`]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, AconfigStorageError> {
+        ciborium::from_reader(bytes).map_err(|errmsg| {
+            BytesParseFail(anyhow!("fail to parse package table from cbor: {}", errmsg))
+        })
+    }
+}
+
+/// Offset information located for a single package by [`read_package_offset`]
+#[derive(PartialEq, Debug)]
+pub struct PackageOffset {
+    pub package_id: u32,
+    pub boolean_offset: u32,
+}
+
+/// Query a single package by name directly against raw package table bytes (e.g. an
+/// mmap'd storage file), without materializing the full list of buckets and nodes
+pub fn read_package_offset(
+    buf: &[u8],
+    package: &str,
+) -> Result<Option<PackageOffset>, AconfigStorageError> {
+    let header = PackageTableHeader::from_bytes(buf)?;
+    if PackageTableVersion::from_version_number(header.version)? != PackageTableVersion::Plain {
+        return Err(AconfigStorageError::UnsupportedVersion(anyhow!(
+            "read_package_offset only supports the plain, uncompressed package table format"
+        )));
+    }
+    let num_buckets = crate::get_table_size(header.num_packages)?;
+    let bucket = PackageTableNode::find_bucket_index(package, num_buckets);
+
+    let mut head = header.bucket_offset as usize + 4 * (bucket as usize);
+    let mut node_offset = read_u32_from_bytes(buf, &mut head)?;
+
+    while node_offset != 0 {
+        let mut head = node_offset as usize;
+        let node_package = read_str_from_bytes(buf, &mut head)?;
+        let package_id = read_u32_from_bytes(buf, &mut head)?;
+        let boolean_offset = read_u32_from_bytes(buf, &mut head)?;
+        let next_offset = read_u32_from_bytes(buf, &mut head)?;
+
+        if node_package == package {
+            return Ok(Some(PackageOffset { package_id, boolean_offset }));
+        }
+        node_offset = next_offset;
+    }
+
+    Ok(None)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -233,4 +503,87 @@ mod tests {
         let version = read_u32_from_bytes(bytes, &mut head).unwrap();
         assert_eq!(version, 1234)
     }
+
+    #[test]
+    // this test point locks down single package lookup via `read_package_offset`
+    fn test_single_package_lookup() {
+        let package_table = create_test_package_table();
+        let bytes = &package_table.as_bytes();
+
+        for node in package_table.nodes.iter() {
+            let package_offset = read_package_offset(bytes, &node.package_name).unwrap().unwrap();
+            assert_eq!(package_offset.package_id, node.package_id);
+            assert_eq!(package_offset.boolean_offset, node.boolean_offset);
+        }
+
+        let package_offset = read_package_offset(bytes, "nonexistent_package").unwrap();
+        assert_eq!(package_offset, None);
+    }
+
+    #[test]
+    // this test point locks down checksum verification for the checksummed/compressed
+    // package table format, with and without zstd compression
+    fn test_checksummed_round_trip() {
+        for compressed in [false, true] {
+            let mut package_table = create_test_package_table();
+            package_table.header.version = PACKAGE_TABLE_VERSION_CHECKSUMMED;
+            package_table.header.compressed = compressed;
+
+            let bytes = package_table.as_bytes();
+            let reinterpreted_table = PackageTable::from_bytes(&bytes).unwrap();
+            assert_eq!(reinterpreted_table.header.compressed, compressed);
+            assert!(reinterpreted_table.header.checksum.is_some());
+            assert_eq!(reinterpreted_table.buckets, package_table.buckets);
+            assert_eq!(reinterpreted_table.nodes, package_table.nodes);
+
+            // flip the last byte of the (always-uncompressed) header checksum field,
+            // so decompression still succeeds and the checksum mismatch is what's exercised
+            let header_len = reinterpreted_table.header.as_bytes().len();
+            let mut corrupted_bytes = bytes;
+            corrupted_bytes[header_len - 1] ^= 0xff;
+            assert!(matches!(
+                PackageTable::from_bytes(&corrupted_bytes),
+                Err(AconfigStorageError::FileIntegrityCheckFail(_))
+            ));
+        }
+    }
+
+    #[test]
+    // this test point locks down that an unknown version number is rejected instead
+    // of being silently misparsed
+    fn test_unsupported_version_rejected() {
+        let mut package_table = create_test_package_table();
+        package_table.header.version = 9999;
+        assert!(matches!(
+            PackageTable::from_bytes(&package_table.as_bytes()),
+            Err(AconfigStorageError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    // this test point locks down lossless round-tripping through the serde-based
+    // JSON and CBOR export/import helpers
+    fn test_serde_round_trip() {
+        let package_table = create_test_package_table();
+
+        let cbor = package_table.to_cbor().unwrap();
+        assert_eq!(package_table, PackageTable::from_cbor(&cbor).unwrap());
+
+        let json = package_table.to_json().unwrap();
+        assert_eq!(package_table, PackageTable::from_json(&json).unwrap());
+    }
+
+    #[test]
+    // this test point locks down that `PackageTableNode::decode` reports exactly
+    // how many bytes it consumed, so a node list can be walked back-to-back
+    fn test_node_decode_consumed_length() {
+        let package_table = create_test_package_table();
+        for node in package_table.nodes.iter() {
+            let bytes = node.as_bytes();
+            let (decoded, len) = PackageTableNode::decode(&bytes).unwrap();
+            assert_eq!(len, bytes.len());
+            assert_eq!(&decoded, node);
+        }
+    }
 }